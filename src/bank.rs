@@ -1,13 +1,26 @@
+use crate::error::ZoroError;
+use crate::params::{CircuitId, ParamsHeader};
 use bazuka::mpn::circuits;
 
 use bazuka::zk::ZkScalar;
+use bellman::gadgets::test::TestConstraintSystem;
 use bellman::groth16;
 use bellman::groth16::Backend;
+use bellman::{Circuit, ConstraintSystem, Index, LinearCombination, SynthesisError, Variable};
 use bls12_381::Bls12;
-use rand::rngs::OsRng;
+use ff::PrimeField;
+use rand::RngCore;
 
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::sync::{Arc, RwLock};
 use thiserror::Error;
+use zeekit::BellmanFr;
+
+/// Number of transitions a batch of size 4^log4_batch_size can hold
+pub const fn batch_capacity(log4_batch_size: u8) -> usize {
+    1 << (2 * log4_batch_size)
+}
 
 #[derive(Error, Debug)]
 pub enum BankError {
@@ -15,6 +28,12 @@ pub enum BankError {
     CannotProve(#[from] bellman::SynthesisError),
     #[error("snark proof incorrect!")]
     IncorrectProof,
+    #[error("circuit is not satisfied! Failing constraint: {}", .0.path)]
+    Unsatisfied(FailingConstraint),
+    #[error("{0}")]
+    Diagnosed(Diagnosis),
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
 }
 #[derive(Clone)]
 pub struct ZoroParams {
@@ -24,15 +43,69 @@ pub struct ZoroParams {
 }
 
 impl ZoroParams {
-    fn verify_keys(&self) -> ZoroVerifyKeys {
-        ZoroVerifyKeys {
-            update: self.update.vk.clone().into(),
-            deposit: self.deposit.vk.clone().into(),
-            withdraw: self.withdraw.vk.clone().into(),
+    pub fn get(&self, circuit: &ZoroCircuit) -> &groth16::Parameters<Bls12> {
+        match circuit {
+            ZoroCircuit::Deposit(_) => &self.deposit,
+            ZoroCircuit::Withdraw(_) => &self.withdraw,
+            ZoroCircuit::Update(_) => &self.update,
         }
     }
 }
 
+pub fn to_bazuka_proof(proof: groth16::Proof<Bls12>) -> bazuka::zk::groth16::Groth16Proof {
+    // bazuka's Groth16Proof mirrors the (a, b, c) layout of bellman's Proof<Bls12>
+    unsafe {
+        std::mem::transmute::<groth16::Proof<Bls12>, bazuka::zk::groth16::Groth16Proof>(proof)
+    }
+}
+
+/// Inverse of `to_bazuka_proof`
+pub fn from_bazuka_proof(proof: bazuka::zk::groth16::Groth16Proof) -> groth16::Proof<Bls12> {
+    unsafe {
+        std::mem::transmute::<bazuka::zk::groth16::Groth16Proof, groth16::Proof<Bls12>>(proof)
+    }
+}
+
+/// Length of `proof_to_bytes`: the compressed `a` (G1), `b` (G2) and `c` (G1)
+/// points of the proof, in that order
+pub const PROOF_BYTES: usize = 48 + 96 + 48;
+
+/// Fixed-length, versionless encoding of a proof, suitable for storing and
+/// indexing proofs
+pub fn proof_to_bytes(proof: &groth16::Proof<Bls12>) -> [u8; PROOF_BYTES] {
+    let mut bytes = [0u8; PROOF_BYTES];
+    proof
+        .write(&mut bytes[..])
+        .expect("A proof always fits in PROOF_BYTES!");
+    bytes
+}
+
+/// Decodes `proof_to_bytes`. Points that are not on the curve, not in the
+/// prime-order subgroup or at infinity are rejected.
+pub fn proof_from_bytes(bytes: &[u8]) -> Result<groth16::Proof<Bls12>, ZoroError> {
+    if bytes.len() != PROOF_BYTES {
+        return Err(ZoroError::ProofLengthMismatch {
+            got: bytes.len(),
+            expected: PROOF_BYTES,
+        });
+    }
+    Ok(groth16::Proof::read(bytes)?)
+}
+
+/// Generates a proof of the circuit, without verifying it. The proof only
+/// depends on the circuit, the params and the rng's output.
+pub fn create_proof<C: Circuit<BellmanFr>, R: RngCore>(
+    circuit: C,
+    params: &groth16::Parameters<Bls12>,
+    rng: &mut R,
+    backend: Backend,
+    cancel: Option<Arc<RwLock<bool>>>,
+) -> Result<groth16::Proof<Bls12>, BankError> {
+    Ok(groth16::create_random_proof_with_backend(
+        circuit, params, rng, backend, cancel,
+    )?)
+}
+
 #[derive(Clone)]
 pub struct ZoroVerifyKeys {
     pub deposit: bazuka::zk::groth16::Groth16VerifyingKey,
@@ -40,6 +113,16 @@ pub struct ZoroVerifyKeys {
     pub update: bazuka::zk::groth16::Groth16VerifyingKey,
 }
 
+impl ZoroVerifyKeys {
+    pub fn get(&self, circuit: &ZoroCircuit) -> &bazuka::zk::groth16::Groth16VerifyingKey {
+        match circuit {
+            ZoroCircuit::Deposit(_) => &self.deposit,
+            ZoroCircuit::Withdraw(_) => &self.withdraw,
+            ZoroCircuit::Update(_) => &self.update,
+        }
+    }
+}
+
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct ZoroWork {
     pub circuit: ZoroCircuit,
@@ -48,22 +131,60 @@ pub struct ZoroWork {
     pub aux_data: ZkScalar,
     pub next_state: ZkScalar,
     pub commitment: ZkScalar,
+    /// Number of real (Non-padding) transitions, if known
+    #[serde(default)]
+    pub enabled: Option<usize>,
+}
+
+/// Leading hex digits of a scalar, enough to tell states apart in logs
+fn short_hex(scalar: &ZkScalar) -> String {
+    scalar.to_string().chars().take(10).collect()
+}
+
+/// One-line summary of a work, for logs. There is no total fee: fees are
+/// inside bazuka's transition types, whose fields zoro never reads.
+impl std::fmt::Display for ZoroWork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let header = self.circuit.params_header();
+        let capacity = batch_capacity(header.log4_batch_size);
+        write!(f, "{} work at height {} (", header.circuit, self.height)?;
+        match self.enabled {
+            Some(enabled) => write!(f, "{}/{} transitions", enabled, capacity)?,
+            None => write!(f, "batch of {}", capacity)?,
+        }
+        write!(
+            f,
+            ", state {}.. -> {}..)",
+            short_hex(&self.state),
+            short_hex(&self.next_state)
+        )
+    }
 }
 
 impl ZoroWork {
-    pub fn verify(
+    /// Synthesizes the circuit with its witnesses, without proving, and reports
+    /// the first unsatisfied constraint (if any)
+    pub fn check_satisfied(&self) -> Result<(), BankError> {
+        log::info!("{}: checking constraints...", self);
+        match self.find_unsatisfied()? {
+            Some(constraint) => Err(BankError::Unsatisfied(constraint)),
+            None => Ok(()),
+        }
+    }
+    fn find_unsatisfied(&self) -> Result<Option<FailingConstraint>, SynthesisError> {
+        match &self.circuit {
+            ZoroCircuit::Deposit(circuit) => find_unsatisfied(circuit.clone()),
+            ZoroCircuit::Withdraw(circuit) => find_unsatisfied(circuit.clone()),
+            ZoroCircuit::Update(circuit) => find_unsatisfied(circuit.clone()),
+        }
+    }
+    pub fn verify_with_key(
         &self,
-        params: &ZoroVerifyKeys,
+        verifier: &bazuka::zk::groth16::Groth16VerifyingKey,
         proof: &bazuka::zk::groth16::Groth16Proof,
     ) -> bool {
-        let verifier: bazuka::zk::groth16::Groth16VerifyingKey = match &self.circuit {
-            ZoroCircuit::Deposit(_) => params.deposit.clone(),
-            ZoroCircuit::Withdraw(_) => params.withdraw.clone(),
-            ZoroCircuit::Update(_) => params.update.clone(),
-        }
-        .into();
         bazuka::zk::groth16::groth16_verify(
-            &verifier,
+            verifier,
             self.commitment,
             self.height,
             self.state,
@@ -72,47 +193,105 @@ impl ZoroWork {
             proof,
         )
     }
-    pub fn prove(
+    pub fn prove<R: RngCore>(
         &self,
-        params: ZoroParams,
+        params: &groth16::Parameters<Bls12>,
+        vk: &bazuka::zk::groth16::Groth16VerifyingKey,
+        rng: &mut R,
         backend: Backend,
         cancel: Option<Arc<RwLock<bool>>>,
     ) -> Result<bazuka::zk::groth16::Groth16Proof, BankError> {
-        let proof = unsafe {
-            std::mem::transmute::<bellman::groth16::Proof<Bls12>, bazuka::zk::groth16::Groth16Proof>(
-                match &self.circuit {
-                    ZoroCircuit::Deposit(circuit) => groth16::create_random_proof_with_backend(
-                        circuit.clone(),
-                        &params.deposit.clone(),
-                        &mut OsRng,
-                        backend.clone(),
-                        cancel.clone(),
-                    )?,
-                    ZoroCircuit::Withdraw(circuit) => groth16::create_random_proof_with_backend(
-                        circuit.clone(),
-                        &params.withdraw.clone(),
-                        &mut OsRng,
-                        backend.clone(),
-                        cancel.clone(),
-                    )?,
-                    ZoroCircuit::Update(circuit) => groth16::create_random_proof_with_backend(
-                        circuit.clone(),
-                        &params.update.clone(),
-                        &mut OsRng,
-                        backend.clone(),
-                        cancel.clone(),
-                    )?,
-                },
-            )
-        };
-        let vks = params.verify_keys();
-
-        if self.verify(&vks, &proof) {
+        log::info!("{}: generating proof...", self);
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let proof = to_bazuka_proof(match &self.circuit {
+            ZoroCircuit::Deposit(circuit) => {
+                create_proof(circuit.clone(), params, rng, backend, cancel)?
+            }
+            ZoroCircuit::Withdraw(circuit) => {
+                create_proof(circuit.clone(), params, rng, backend, cancel)?
+            }
+            ZoroCircuit::Update(circuit) => {
+                create_proof(circuit.clone(), params, rng, backend, cancel)?
+            }
+        });
+        #[cfg(feature = "metrics")]
+        self.record_proving_time(start);
+        log::info!("{}: verifying proof...", self);
+        if self.verify_with_key(vk, &proof) {
             Ok(proof)
         } else {
             Err(BankError::IncorrectProof)
         }
     }
+    #[cfg(feature = "metrics")]
+    fn record_proving_time(&self, start: std::time::Instant) {
+        metrics::histogram!(
+            "zoro_proving_seconds",
+            start.elapsed().as_secs_f64(),
+            "circuit" => self.circuit.params_header().circuit.to_string()
+        );
+    }
+    /// Like `prove`, but diagnoses incorrect proofs (See `prove_or_diagnose`)
+    pub fn prove_or_diagnose<R: RngCore>(
+        &self,
+        params: &groth16::Parameters<Bls12>,
+        vk: &bazuka::zk::groth16::Groth16VerifyingKey,
+        rng: &mut R,
+        backend: Backend,
+        cancel: Option<Arc<RwLock<bool>>>,
+    ) -> Result<bazuka::zk::groth16::Groth16Proof, Diagnosis> {
+        log::info!("{}: generating proof...", self);
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let verify = |proof: &groth16::Proof<Bls12>| {
+            #[cfg(feature = "metrics")]
+            self.record_proving_time(start);
+            log::info!("{}: verifying proof...", self);
+            self.verify_with_key(vk, &to_bazuka_proof(proof.clone()))
+        };
+        Ok(to_bazuka_proof(match &self.circuit {
+            ZoroCircuit::Deposit(circuit) => {
+                prove_or_diagnose(circuit.clone(), params, rng, backend, cancel, verify)?
+            }
+            ZoroCircuit::Withdraw(circuit) => {
+                prove_or_diagnose(circuit.clone(), params, rng, backend, cancel, verify)?
+            }
+            ZoroCircuit::Update(circuit) => {
+                prove_or_diagnose(circuit.clone(), params, rng, backend, cancel, verify)?
+            }
+        }))
+    }
+}
+
+/// Proves the circuit, and if `verify` rejects the proof, synthesizes the
+/// circuit again (Which is as memory hungry as proving) to find out why
+pub fn prove_or_diagnose<C: Circuit<BellmanFr> + Clone, R: RngCore>(
+    circuit: C,
+    params: &groth16::Parameters<Bls12>,
+    rng: &mut R,
+    backend: Backend,
+    cancel: Option<Arc<RwLock<bool>>>,
+    verify: impl FnOnce(&groth16::Proof<Bls12>) -> bool,
+) -> Result<groth16::Proof<Bls12>, Diagnosis> {
+    let proof = create_proof(circuit.clone(), params, rng, backend, cancel)?;
+    if verify(&proof) {
+        return Ok(proof);
+    }
+    log::info!("Diagnosing incorrect proof...");
+    Err(match find_unsatisfied(circuit) {
+        Ok(Some(constraint)) => Diagnosis {
+            error: BankError::IncorrectProof.to_string(),
+            constraint: Some(constraint),
+        },
+        Ok(None) => Diagnosis {
+            error: "snark proof incorrect, although the circuit is satisfied! \
+                The params may not match the verifying key."
+                .into(),
+            constraint: None,
+        },
+        Err(e) => BankError::from(e).into(),
+    })
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -121,3 +300,708 @@ pub enum ZoroCircuit {
     Withdraw(circuits::WithdrawCircuit),
     Update(circuits::UpdateCircuit),
 }
+
+impl ZoroCircuit {
+    /// Header of the parameters file this circuit should be proven with
+    pub fn params_header(&self) -> ParamsHeader {
+        match self {
+            ZoroCircuit::Deposit(c) => ParamsHeader {
+                circuit: CircuitId::Deposit,
+                log4_tree_size: c.log4_tree_size,
+                log4_token_tree_size: c.log4_token_tree_size,
+                log4_batch_size: c.log4_deposit_batch_size,
+            },
+            ZoroCircuit::Withdraw(c) => ParamsHeader {
+                circuit: CircuitId::Withdraw,
+                log4_tree_size: c.log4_tree_size,
+                log4_token_tree_size: c.log4_token_tree_size,
+                log4_batch_size: c.log4_withdraw_batch_size,
+            },
+            ZoroCircuit::Update(c) => ParamsHeader {
+                circuit: CircuitId::Update,
+                log4_tree_size: c.log4_tree_size,
+                log4_token_tree_size: c.log4_token_tree_size,
+                log4_batch_size: c.log4_update_batch_size,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConstraintCount {
+    pub constraints: usize,
+    pub inputs: usize,
+    pub aux: usize,
+}
+
+/// Constraint system that only counts, without evaluating any witness values.
+/// Optionally keeps the (A, B, C) linear combinations of every constraint.
+struct ConstraintCounter {
+    count: ConstraintCount,
+    constraints: Option<Vec<[LinearCombination<BellmanFr>; 3]>>,
+}
+
+impl ConstraintCounter {
+    fn new(keep_constraints: bool) -> Self {
+        Self {
+            count: ConstraintCount {
+                constraints: 0,
+                inputs: 1, // The constant ONE input
+                aux: 0,
+            },
+            constraints: keep_constraints.then(Vec::new),
+        }
+    }
+}
+
+impl ConstraintSystem<BellmanFr> for ConstraintCounter {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _: A, _: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.count.aux += 1;
+        Ok(Variable::new_unchecked(Index::Aux(self.count.aux - 1)))
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _: A, _: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.count.inputs += 1;
+        Ok(Variable::new_unchecked(Index::Input(self.count.inputs - 1)))
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _: A, a: LA, b: LB, c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+    {
+        self.count.constraints += 1;
+        if let Some(constraints) = &mut self.constraints {
+            constraints.push([
+                a(LinearCombination::zero()),
+                b(LinearCombination::zero()),
+                c(LinearCombination::zero()),
+            ]);
+        }
+    }
+
+    fn push_namespace<NR, N>(&mut self, _: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+pub fn count_constraints<C: Circuit<BellmanFr>>(
+    circuit: C,
+) -> Result<ConstraintCount, SynthesisError> {
+    let mut counter = ConstraintCounter::new(false);
+    circuit.synthesize(&mut counter)?;
+    Ok(counter.count)
+}
+
+/// Writes the R1CS of a circuit in zoro's own format, all integers being
+/// little-endian:
+/// - The number of inputs (Including the constant ONE, input 0), the number
+///   of aux variables and the number of constraints, as `u64`s.
+/// - For each constraint, its A, B and C linear combinations. A linear
+///   combination is its number of terms as a `u64`, followed by the terms. A
+///   term is the kind of its variable as a `u8` (0 for inputs, 1 for aux), the
+///   index of the variable as a `u64` and the 32-byte `to_repr` of its
+///   coefficient.
+pub fn export_r1cs<C: Circuit<BellmanFr>, W: Write>(
+    circuit: C,
+    mut w: W,
+) -> Result<ConstraintCount, BankError> {
+    let mut counter = ConstraintCounter::new(true);
+    circuit.synthesize(&mut counter)?;
+    let count = counter.count;
+    for n in [count.inputs, count.aux, count.constraints] {
+        w.write_all(&(n as u64).to_le_bytes())?;
+    }
+    for lcs in counter.constraints.unwrap_or_default() {
+        for lc in lcs {
+            w.write_all(&(lc.as_ref().len() as u64).to_le_bytes())?;
+            for (var, coeff) in lc.as_ref() {
+                let (kind, index) = match var.get_unchecked() {
+                    Index::Input(i) => (0u8, i),
+                    Index::Aux(i) => (1u8, i),
+                };
+                w.write_all(&[kind])?;
+                w.write_all(&(index as u64).to_le_bytes())?;
+                w.write_all(coeff.to_repr().as_ref())?;
+            }
+        }
+    }
+    w.flush()?;
+    Ok(count)
+}
+
+/// Number of witness values reported along with a failing constraint
+const REPORTED_WITNESSES: usize = 8;
+
+/// First unsatisfied constraint of a circuit, with the first few witness
+/// values allocated in the same namespace
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FailingConstraint {
+    pub path: String,
+    /// Index of the transition the constraint belongs to, as guessed by
+    /// `transition_index`
+    pub transition: Option<usize>,
+    pub witnesses: Vec<(String, String)>,
+}
+
+/// Index of the transition a constraint belongs to, guessed from the first
+/// namespace of its path whose name ends in a number (E.g. `transition 3/...`).
+/// Nothing ties such a namespace to a transition: a circuit numbering some
+/// other namespace (E.g. `layer 2/...`) first gets a wrong index, and one that
+/// doesn't number its transitions gets `None`. The `#n` suffixes given to
+/// repeated names are ignored.
+fn transition_index(path: &str) -> Option<usize> {
+    let mut namespaces: Vec<&str> = path.split('/').collect();
+    namespaces.pop(); // Name of the constraint itself
+    namespaces
+        .into_iter()
+        .find_map(|ns| ns.split('#').next()?.rsplit(' ').next()?.parse().ok())
+}
+
+/// Why a work couldn't be proven
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Diagnosis {
+    pub error: String,
+    /// First unsatisfied constraint, if the circuit was found unsatisfied
+    pub constraint: Option<FailingConstraint>,
+}
+
+impl From<BankError> for Diagnosis {
+    fn from(e: BankError) -> Self {
+        match e {
+            BankError::Unsatisfied(constraint) => Diagnosis {
+                error: "circuit is not satisfied!".into(),
+                constraint: Some(constraint),
+            },
+            BankError::Diagnosed(diagnosis) => diagnosis,
+            e => Diagnosis {
+                error: e.to_string(),
+                constraint: None,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)?;
+        if let Some(constraint) = &self.constraint {
+            write!(f, " Failing constraint: {}", constraint.path)?;
+            if let Some(transition) = constraint.transition {
+                write!(f, " (Transition {})", transition)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forwards to a `TestConstraintSystem`, remembering the path of every
+/// allocated variable, so that their values can be looked up afterwards
+struct RecordingCs {
+    cs: TestConstraintSystem<BellmanFr>,
+    namespace: Vec<String>,
+    variables: Vec<String>,
+    paths: HashSet<String>,
+    repeats: HashMap<String, usize>,
+}
+
+impl RecordingCs {
+    fn new() -> Self {
+        Self {
+            cs: TestConstraintSystem::new(),
+            namespace: Vec::new(),
+            variables: Vec::new(),
+            paths: HashSet::from(["ONE".to_string()]),
+            repeats: HashMap::new(),
+        }
+    }
+    fn path(&self, name: &str) -> String {
+        let mut path = self.namespace.clone();
+        path.push(name.to_string());
+        path.join("/")
+    }
+    /// `TestConstraintSystem` panics on repeated paths (And on names with a
+    /// `/`), while the MPN circuits label most of their constraints `""`.
+    /// Repeated names get a `#n` suffix instead.
+    fn unique_name(&mut self, name: String) -> String {
+        let name = name.replace('/', "_");
+        let base = self.path(&name);
+        let mut n = self.repeats.get(&base).copied().unwrap_or(0);
+        loop {
+            let unique = if n == 0 {
+                name.clone()
+            } else {
+                format!("{}#{}", name, n)
+            };
+            n += 1;
+            if self.paths.insert(self.path(&unique)) {
+                self.repeats.insert(base, n);
+                return unique;
+            }
+        }
+    }
+}
+
+impl ConstraintSystem<BellmanFr> for RecordingCs {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, annotation: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        let name = self.unique_name(annotation().into());
+        let path = self.path(&name);
+        let var = self.cs.alloc(|| name, f)?;
+        self.variables.push(path);
+        Ok(var)
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, annotation: A, f: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<BellmanFr, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        let name = self.unique_name(annotation().into());
+        let path = self.path(&name);
+        let var = self.cs.alloc_input(|| name, f)?;
+        self.variables.push(path);
+        Ok(var)
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, annotation: A, a: LA, b: LB, c: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LB: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+        LC: FnOnce(LinearCombination<BellmanFr>) -> LinearCombination<BellmanFr>,
+    {
+        let name = self.unique_name(annotation().into());
+        self.cs.enforce(|| name, a, b, c);
+    }
+
+    fn push_namespace<NR, N>(&mut self, name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+        let name = self.unique_name(name_fn().into());
+        self.namespace.push(name.clone());
+        self.cs.push_namespace(|| name);
+    }
+
+    fn pop_namespace(&mut self) {
+        self.namespace.pop();
+        self.cs.pop_namespace();
+    }
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
+/// Synthesizes the circuit with its witnesses and returns its first
+/// unsatisfied constraint, if any
+pub fn find_unsatisfied<C: Circuit<BellmanFr>>(
+    circuit: C,
+) -> Result<Option<FailingConstraint>, SynthesisError> {
+    let mut cs = RecordingCs::new();
+    circuit.synthesize(&mut cs)?;
+    let path = match cs.cs.which_is_unsatisfied() {
+        Some(path) => path.to_string(),
+        None => return Ok(None),
+    };
+    let namespace = match path.rfind('/') {
+        Some(i) => &path[..=i],
+        None => "",
+    };
+    let witnesses = cs
+        .variables
+        .iter()
+        .filter(|var| var.starts_with(namespace))
+        .take(REPORTED_WITNESSES)
+        .map(|var| (var.clone(), format!("{:?}", cs.cs.get(var))))
+        .collect();
+    Ok(Some(FailingConstraint {
+        transition: transition_index(&path),
+        path,
+        witnesses,
+    }))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    /// Batch of `a * b == c` transitions, `c` being a public input
+    #[derive(Clone)]
+    pub struct TestBatch {
+        pub transitions: Vec<(u64, u64, u64)>,
+    }
+
+    impl TestBatch {
+        pub fn new(log4_batch_size: u8) -> Self {
+            Self {
+                transitions: (0..batch_capacity(log4_batch_size) as u64)
+                    .map(|i| (i, i + 1, i * (i + 1)))
+                    .collect(),
+            }
+        }
+        pub fn params(log4_batch_size: u8) -> groth16::Parameters<Bls12> {
+            groth16::generate_random_parameters::<Bls12, _, _>(
+                Self::new(log4_batch_size),
+                &mut ChaCha20Rng::seed_from_u64(123456),
+            )
+            .unwrap()
+        }
+    }
+
+    impl Circuit<BellmanFr> for TestBatch {
+        fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+            self,
+            cs: &mut CS,
+        ) -> Result<(), SynthesisError> {
+            for (i, (a, b, c)) in self.transitions.into_iter().enumerate() {
+                let mut cs = cs.namespace(|| format!("transition {}", i));
+                let a = cs.alloc(|| "a", || Ok(BellmanFr::from(a)))?;
+                let b = cs.alloc(|| "b", || Ok(BellmanFr::from(b)))?;
+                let c = cs.alloc_input(|| "c", || Ok(BellmanFr::from(c)))?;
+                cs.enforce(|| "product", |lc| lc + a, |lc| lc + b, |lc| lc + c);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_count_constraints() {
+        let count = count_constraints(TestBatch::new(1)).unwrap();
+        assert_eq!(count.constraints, 4);
+        assert_eq!(count.inputs, 5); // Including ONE
+        assert_eq!(count.aux, 8);
+    }
+
+    #[test]
+    fn test_batch_capacity() {
+        const CAPACITY: usize = batch_capacity(3);
+        assert_eq!(CAPACITY, 64);
+        assert_eq!(batch_capacity(0), 1);
+        assert_eq!(batch_capacity(1), 4);
+        assert_eq!(batch_capacity(2), 16);
+    }
+
+    /// The same batch, labeled like the MPN circuits: with repeated (Mostly
+    /// empty) names
+    struct UnlabeledBatch(TestBatch);
+
+    impl Circuit<BellmanFr> for UnlabeledBatch {
+        fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+            self,
+            cs: &mut CS,
+        ) -> Result<(), SynthesisError> {
+            for (a, b, c) in self.0.transitions {
+                let mut cs = cs.namespace(|| "transition");
+                let a = cs.alloc(|| "", || Ok(BellmanFr::from(a)))?;
+                let b = cs.alloc(|| "", || Ok(BellmanFr::from(b)))?;
+                let c = cs.alloc_input(|| "c/input", || Ok(BellmanFr::from(c)))?;
+                cs.enforce(|| "", |lc| lc + a, |lc| lc + b, |lc| lc + c);
+            }
+            Ok(())
+        }
+    }
+
+    /// A circuit with the five public inputs of a zoro work, all equal to
+    /// `value`, so that their order doesn't matter
+    struct PublicInputs {
+        value: u64,
+    }
+
+    impl Circuit<BellmanFr> for PublicInputs {
+        fn synthesize<CS: ConstraintSystem<BellmanFr>>(
+            self,
+            cs: &mut CS,
+        ) -> Result<(), SynthesisError> {
+            for i in 0..5 {
+                let v = cs.alloc_input(|| format!("input {}", i), || Ok(self.value.into()))?;
+                cs.enforce(
+                    || format!("input {} is {}", i, self.value),
+                    |lc| lc + v,
+                    |lc| lc + CS::one(),
+                    |lc| lc + (BellmanFr::from(self.value), CS::one()),
+                );
+            }
+            Ok(())
+        }
+    }
+
+    fn seeded_proof(params: &groth16::Parameters<Bls12>, seed: u64) -> Vec<u8> {
+        let proof = create_proof(
+            TestBatch::new(1),
+            params,
+            &mut ChaCha20Rng::seed_from_u64(seed),
+            Backend::Cpu,
+            None,
+        )
+        .unwrap();
+        let mut bytes = Vec::new();
+        proof.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_seeded_proofs_are_identical() {
+        let params = TestBatch::params(1);
+        assert_eq!(seeded_proof(&params, 1), seeded_proof(&params, 1));
+        assert_ne!(seeded_proof(&params, 1), seeded_proof(&params, 2));
+    }
+
+    #[test]
+    fn test_broken_batch_reports_failing_constraint() {
+        assert!(find_unsatisfied(TestBatch::new(1)).unwrap().is_none());
+
+        let mut batch = TestBatch::new(1);
+        batch.transitions[2].2 += 1;
+        let failing = find_unsatisfied(batch).unwrap().unwrap();
+        assert_eq!(failing.path, "transition 2/product");
+        let witnesses: Vec<_> = failing.witnesses.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(
+            witnesses,
+            ["transition 2/a", "transition 2/b", "transition 2/c"]
+        );
+    }
+
+    #[test]
+    fn test_broken_batch_diagnosis() {
+        let mut batch = TestBatch::new(2);
+        batch.transitions[5].0 += 1;
+        batch.transitions[9].2 += 1;
+        let diagnosis = Diagnosis::from(BankError::Unsatisfied(
+            find_unsatisfied(batch).unwrap().unwrap(),
+        ));
+        let constraint = diagnosis.constraint.as_ref().unwrap();
+        assert_eq!(constraint.path, "transition 5/product");
+        assert_eq!(constraint.transition, Some(5));
+        assert_eq!(
+            diagnosis.to_string(),
+            "circuit is not satisfied! Failing constraint: transition 5/product (Transition 5)"
+        );
+
+        assert_eq!(transition_index("transition 12/check/eq"), Some(12));
+        assert_eq!(transition_index("root/eq"), None);
+        assert_eq!(transition_index("eq 3"), None);
+    }
+
+    #[test]
+    fn test_find_unsatisfied_with_repeated_names() {
+        assert!(find_unsatisfied(UnlabeledBatch(TestBatch::new(1)))
+            .unwrap()
+            .is_none());
+
+        let mut batch = TestBatch::new(1);
+        batch.transitions[2].2 += 1;
+        let failing = find_unsatisfied(UnlabeledBatch(batch)).unwrap().unwrap();
+        assert_eq!(failing.path, "transition#2/#2");
+        assert_eq!(failing.transition, None);
+        let witnesses: Vec<_> = failing.witnesses.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(
+            witnesses,
+            ["transition#2/", "transition#2/#1", "transition#2/c_input"]
+        );
+    }
+
+    #[test]
+    fn test_work_serde_roundtrip() {
+        use bazuka::mpn::circuits::MpnCircuit;
+        let work = ZoroWork {
+            circuit: ZoroCircuit::Deposit(circuits::DepositCircuit::empty(1, 1, 1)),
+            height: 12,
+            state: ZkScalar::new(&[1]),
+            aux_data: ZkScalar::new(&[2]),
+            next_state: ZkScalar::new(&[3]),
+            commitment: ZkScalar::new(&[4]),
+            enabled: Some(3),
+        };
+        let json = serde_json::to_string(&work).unwrap();
+        let decoded: ZoroWork = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        assert_eq!(decoded.to_string(), work.to_string());
+
+        // Work files written before `enabled` existed
+        let mut value = serde_json::to_value(&work).unwrap();
+        value.as_object_mut().unwrap().remove("enabled");
+        let legacy: ZoroWork = serde_json::from_value(value).unwrap();
+        assert_eq!(legacy.enabled, None);
+        assert_eq!(legacy.height, 12);
+    }
+
+    #[test]
+    fn test_bazuka_proof_verifies() {
+        let mut rng = ChaCha20Rng::seed_from_u64(123456);
+        let params =
+            groth16::generate_random_parameters::<Bls12, _, _>(PublicInputs { value: 7 }, &mut rng)
+                .unwrap();
+        let proof = to_bazuka_proof(
+            create_proof(
+                PublicInputs { value: 7 },
+                &params,
+                &mut rng,
+                Backend::Cpu,
+                None,
+            )
+            .unwrap(),
+        );
+        let vk: bazuka::zk::groth16::Groth16VerifyingKey = params.vk.into();
+        let seven = ZkScalar::from(7u64);
+        assert!(bazuka::zk::groth16::groth16_verify(
+            &vk, seven, 7, seven, seven, seven, &proof
+        ));
+        assert!(!bazuka::zk::groth16::groth16_verify(
+            &vk, seven, 8, seven, seven, seven, &proof
+        ));
+    }
+
+    #[test]
+    fn test_proof_bytes_roundtrip() {
+        let params = TestBatch::params(1);
+        let proof = create_proof(
+            TestBatch::new(1),
+            &params,
+            &mut ChaCha20Rng::seed_from_u64(1),
+            Backend::Cpu,
+            None,
+        )
+        .unwrap();
+        let bytes = proof_to_bytes(&proof);
+        assert_eq!(proof_from_bytes(&bytes).unwrap(), proof);
+        assert_eq!(
+            proof_to_bytes(&from_bazuka_proof(to_bazuka_proof(proof))),
+            bytes
+        );
+
+        assert!(matches!(
+            proof_from_bytes(&bytes[1..]),
+            Err(ZoroError::ProofLengthMismatch {
+                got: 191,
+                expected: PROOF_BYTES
+            })
+        ));
+        assert!(matches!(
+            proof_from_bytes(&[bytes.to_vec(), vec![0]].concat()),
+            Err(ZoroError::ProofLengthMismatch { got: 193, .. })
+        ));
+        assert!(matches!(
+            proof_from_bytes(&[0u8; PROOF_BYTES]),
+            Err(ZoroError::IoError(_))
+        ));
+        let mut corrupted = bytes;
+        corrupted[PROOF_BYTES - 1] ^= 1; // Moves c off the curve or the subgroup
+        assert!(matches!(
+            proof_from_bytes(&corrupted),
+            Err(ZoroError::IoError(_))
+        ));
+    }
+
+    /// Proves `batch` with `params`, verifying against the inputs of the batch
+    /// with `vk`
+    fn prove_test_batch(
+        batch: TestBatch,
+        params: &groth16::Parameters<Bls12>,
+        vk: &groth16::VerifyingKey<Bls12>,
+    ) -> Result<groth16::Proof<Bls12>, Diagnosis> {
+        let pvk = groth16::prepare_verifying_key(vk);
+        let inputs: Vec<BellmanFr> = batch
+            .transitions
+            .iter()
+            .map(|(_, _, c)| BellmanFr::from(*c))
+            .collect();
+        prove_or_diagnose(
+            batch,
+            params,
+            &mut ChaCha20Rng::seed_from_u64(1),
+            Backend::Cpu,
+            None,
+            |proof| groth16::verify_proof(&pvk, proof, &inputs).is_ok(),
+        )
+    }
+
+    #[test]
+    fn test_prove_or_diagnose() {
+        let params = TestBatch::params(1);
+        assert!(prove_test_batch(TestBatch::new(1), &params, &params.vk).is_ok());
+
+        let mut broken = TestBatch::new(1);
+        broken.transitions[2].0 += 1;
+        let diagnosis = prove_test_batch(broken, &params, &params.vk).unwrap_err();
+        assert_eq!(diagnosis.error, BankError::IncorrectProof.to_string());
+        let constraint = diagnosis.constraint.unwrap();
+        assert_eq!(constraint.path, "transition 2/product");
+        assert_eq!(constraint.transition, Some(2));
+    }
+
+    #[test]
+    fn test_prove_or_diagnose_mismatched_params() {
+        let params = TestBatch::params(1);
+        let other = groth16::generate_random_parameters::<Bls12, _, _>(
+            TestBatch::new(1),
+            &mut ChaCha20Rng::seed_from_u64(654321),
+        )
+        .unwrap();
+        let diagnosis = prove_test_batch(TestBatch::new(1), &other, &params.vk).unwrap_err();
+        assert!(diagnosis.constraint.is_none());
+        assert!(diagnosis
+            .error
+            .contains("although the circuit is satisfied"));
+    }
+
+    #[test]
+    fn test_export_r1cs() {
+        let mut file = Vec::new();
+        let count = export_r1cs(TestBatch::new(1), &mut file).unwrap();
+        let expected = count_constraints(TestBatch::new(1)).unwrap();
+        assert_eq!(
+            (count.constraints, count.inputs, count.aux),
+            (expected.constraints, expected.inputs, expected.aux)
+        );
+
+        let header: Vec<u64> = file[..24]
+            .chunks(8)
+            .map(|n| u64::from_le_bytes(n.try_into().unwrap()))
+            .collect();
+        assert_eq!(header, [5, 8, 4]);
+        // Each a * b == c constraint has a single term in each of A, B and C
+        assert_eq!(file.len(), 24 + 4 * 3 * (8 + 1 + 8 + 32));
+
+        // A of the first constraint: 1 * aux 0
+        assert_eq!(&file[24..33], &[1, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&file[33..41], &0u64.to_le_bytes());
+        assert_eq!(&file[41..73], BellmanFr::from(1u64).to_repr().as_ref());
+    }
+}
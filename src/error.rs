@@ -0,0 +1,36 @@
+use crate::bank;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ZoroError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("node error: {0}")]
+    NodeError(#[from] bazuka::client::NodeError),
+    #[error("bank error: {0}")]
+    BankError(#[from] bank::BankError),
+    #[error("async task join error: {0}")]
+    JoinError(#[from] tokio::task::JoinError),
+    #[error("http server error: {0}")]
+    HttpServerError(#[from] hyper::Error),
+    #[error("http client error: {0}")]
+    HttpError(#[from] hyper::http::Error),
+    #[error("bincode error: {0}")]
+    BincodeError(#[from] bincode::Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("someone else found a block sooner than you!")]
+    Aborted,
+    #[error("you are not the validator anymore!")]
+    NotValidator,
+    #[error("http request timed out!")]
+    HttpTimeout(#[from] tokio::time::error::Elapsed),
+    #[error("from-hex error happened: {0}")]
+    FromHexError(#[from] hex::FromHexError),
+    #[error("kv-store error happened: {0}")]
+    KvStoreError(#[from] bazuka::db::KvStoreError),
+    #[error("work has {got} transitions but the batch can only fit {max}!")]
+    BatchOverflow { got: usize, max: usize },
+    #[error("proof has {got} bytes but should have {expected}!")]
+    ProofLengthMismatch { got: usize, expected: usize },
+}
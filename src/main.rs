@@ -2,6 +2,8 @@ use bazuka::mpn::circuits;
 
 mod bank;
 mod client;
+mod error;
+mod params;
 
 use bazuka::client::PeerAddress;
 
@@ -16,12 +18,15 @@ use bellman::{groth16, Circuit};
 use bls12_381::Bls12;
 use client::SyncClient;
 use colored::Colorize;
+use error::ZoroError;
 
-use rand::{Rng, SeedableRng};
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fs::File;
+use std::io::{BufReader, BufWriter};
 
 use std::path::PathBuf;
 use std::sync::Mutex;
@@ -43,6 +48,76 @@ struct GenerateParamsOpt {
     withdraw_circuit_params: PathBuf,
 }
 
+#[derive(Debug, Clone, StructOpt)]
+struct CountConstraintsOpt {
+    #[structopt(long)]
+    log4_tree_size: Option<u8>,
+    #[structopt(long)]
+    log4_token_tree_size: Option<u8>,
+    #[structopt(long)]
+    log4_deposit_batch_size: Option<u8>,
+    #[structopt(long)]
+    log4_withdraw_batch_size: Option<u8>,
+    #[structopt(long)]
+    log4_update_batch_size: Option<u8>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct ExportR1csOpt {
+    #[structopt(long, default_value = "update.r1cs")]
+    update_r1cs: PathBuf,
+    #[structopt(long, default_value = "deposit.r1cs")]
+    deposit_r1cs: PathBuf,
+    #[structopt(long, default_value = "withdraw.r1cs")]
+    withdraw_r1cs: PathBuf,
+    #[structopt(long)]
+    log4_tree_size: Option<u8>,
+    #[structopt(long)]
+    log4_token_tree_size: Option<u8>,
+    #[structopt(long)]
+    log4_deposit_batch_size: Option<u8>,
+    #[structopt(long)]
+    log4_withdraw_batch_size: Option<u8>,
+    #[structopt(long)]
+    log4_update_batch_size: Option<u8>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct ProveWorkOpt {
+    #[structopt(long)]
+    params: PathBuf,
+    #[structopt(long)]
+    work: PathBuf,
+    #[structopt(long)]
+    out: PathBuf,
+    #[structopt(long)]
+    gpu: bool,
+    #[structopt(long)]
+    seed: Option<u64>,
+    #[structopt(long)]
+    report: Option<PathBuf>,
+    /// Write the proof in the fixed 192-byte layout instead of bincode
+    #[structopt(long)]
+    raw: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+struct VerifyWorkOpt {
+    #[structopt(long)]
+    work: PathBuf,
+    #[structopt(long)]
+    proof: PathBuf,
+    /// The proof is in the fixed 192-byte layout instead of bincode
+    #[structopt(long)]
+    raw: bool,
+}
+
+#[derive(serde::Serialize)]
+struct DiagnosisReport {
+    work: String,
+    diagnosis: bank::Diagnosis,
+}
+
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 struct Optimization {
     n_g1: usize,
@@ -84,8 +159,12 @@ struct ProveOpt {
     gpu: bool,
     #[structopt(long, default_value = "1")]
     workers: usize,
+    #[structopt(long, default_value = "32")]
+    threads: usize,
     #[structopt(long)]
     address: Address,
+    #[structopt(long)]
+    check_satisfied: bool,
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -93,36 +172,224 @@ struct ProveOpt {
 enum Opt {
     Prove(ProveOpt),
     GenerateParams(GenerateParamsOpt),
+    CountConstraints(CountConstraintsOpt),
+    ExportR1cs(ExportR1csOpt),
+    ProveWork(ProveWorkOpt),
+    VerifyWork(VerifyWorkOpt),
 }
 
 const MAXIMUM_PROVING_TIME: Duration = Duration::from_secs(50);
 
 fn load_params<C: Circuit<BellmanFr> + MpnCircuit, R: Rng>(
     path: PathBuf,
+    circuit: params::CircuitId,
     rng: Option<R>,
     log4_tree_size: u8,
     log4_token_tree_size: u8,
     log4_batch_size: u8,
 ) -> groth16::Parameters<Bls12> {
+    let header = params::ParamsHeader {
+        circuit,
+        log4_tree_size,
+        log4_token_tree_size,
+        log4_batch_size,
+    };
     if let Some(mut rng) = rng {
         println!("Generating {}...", path.to_string_lossy());
         let c = C::empty(log4_tree_size, log4_token_tree_size, log4_batch_size);
 
         let p = groth16::generate_random_parameters::<Bls12, _, _>(c, &mut rng).unwrap();
         let param_file = File::create(path.clone()).expect("Unable to create parameters file!");
-        p.write(param_file)
+        params::write_params(BufWriter::new(param_file), &p, &header)
             .expect("Unable to write parameters file!");
         log::info!("VK of {}: {}", path.to_string_lossy(), vk_to_hex(&p.vk));
         p
     } else {
-        println!("Loading {}...", path.to_string_lossy());
-        let param_file = File::open(path.clone()).expect("Unable to open parameters file!");
-        let p = groth16::Parameters::<Bls12>::read(
-            param_file, false, /* false for better performance*/
+        read_params_file(path, &header)
+    }
+}
+
+fn read_params_file(path: PathBuf, header: &params::ParamsHeader) -> groth16::Parameters<Bls12> {
+    println!("Loading {}...", path.to_string_lossy());
+    let param_file = File::open(path.clone()).expect("Unable to open parameters file!");
+    let (p, file_header) = params::read_params(
+        BufReader::new(param_file),
+        header,
+        &params::network_vk_fingerprint(header.circuit),
+    )
+    .unwrap_or_else(|e| {
+        panic!(
+            "Unable to read parameters file: {} Regenerate params via: zoro generate-params",
+            e
         )
-        .expect("Unable to read parameters file!");
-        log::info!("VK of {}: {}", path.to_string_lossy(), vk_to_hex(&p.vk));
-        p
+    });
+    if file_header.is_none() {
+        println!(
+            "{} {} has no header, assuming it is for {}! Regenerate params via: zoro generate-params",
+            "WARNING:".bright_red(),
+            path.to_string_lossy(),
+            header
+        );
+    }
+    log::info!("VK of {}: {}", path.to_string_lossy(), vk_to_hex(&p.vk));
+    p
+}
+
+fn log_memory_estimate(
+    circuit: params::CircuitId,
+    p: &groth16::Parameters<Bls12>,
+    backend: &Backend,
+) {
+    let estimate = if let Backend::Gpu(devices) = backend {
+        let devices = devices.lock().unwrap();
+        params::estimate_proving_memory(p, devices.iter().map(|(_, opt)| opt))
+    } else {
+        params::estimate_proving_memory(p, [])
+    };
+    log::info!(
+        "Estimated memory per {} proof: {}MB",
+        circuit,
+        estimate / (1024 * 1024)
+    );
+}
+
+fn count_constraints<C: Circuit<BellmanFr> + MpnCircuit>(
+    name: &str,
+    log4_tree_size: u8,
+    log4_token_tree_size: u8,
+    log4_batch_size: u8,
+) {
+    let c = C::empty(log4_tree_size, log4_token_tree_size, log4_batch_size);
+    let count = bank::count_constraints(c).expect("Unable to synthesize the circuit!");
+    println!(
+        "{} (tree: {}, token-tree: {}, batch: {}): {} constraints, {} inputs, {} aux",
+        name.bright_yellow(),
+        log4_tree_size,
+        log4_token_tree_size,
+        log4_batch_size,
+        count.constraints,
+        count.inputs,
+        count.aux
+    );
+}
+
+fn export_r1cs<C: Circuit<BellmanFr> + MpnCircuit>(
+    path: PathBuf,
+    log4_tree_size: u8,
+    log4_token_tree_size: u8,
+    log4_batch_size: u8,
+) {
+    println!("Exporting {}...", path.to_string_lossy());
+    let c = C::empty(log4_tree_size, log4_token_tree_size, log4_batch_size);
+    let r1cs_file = File::create(path.clone()).expect("Unable to create R1CS file!");
+    let count =
+        bank::export_r1cs(c, BufWriter::new(r1cs_file)).expect("Unable to export the R1CS!");
+    println!(
+        "{}: {} constraints, {} inputs, {} aux",
+        path.to_string_lossy().bright_yellow(),
+        count.constraints,
+        count.inputs,
+        count.aux
+    );
+}
+
+#[cfg(feature = "metrics")]
+fn record_constraint_count<C: Circuit<BellmanFr> + MpnCircuit>(
+    circuit: params::CircuitId,
+    log4_tree_size: u8,
+    log4_token_tree_size: u8,
+    log4_batch_size: u8,
+) {
+    let c = C::empty(log4_tree_size, log4_token_tree_size, log4_batch_size);
+    let count = bank::count_constraints(c).expect("Unable to synthesize the circuit!");
+    metrics::gauge!(
+        "zoro_constraints",
+        count.constraints as f64,
+        "circuit" => circuit.to_string()
+    );
+}
+
+fn make_backend(gpu: bool) -> Backend {
+    if gpu {
+        let devices: Vec<_> = Device::by_brand(Brand::Nvidia)
+            .into_iter()
+            .flatten()
+            .collect();
+        if devices.is_empty() {
+            println!(
+                "{} No Nvidia GPU found! Falling back to CPU...",
+                "WARNING:".bright_red()
+            );
+            return Backend::Cpu;
+        }
+        Backend::Gpu(Arc::new(Mutex::new(
+            devices
+                .into_iter()
+                .map(|d| {
+                    (
+                        d,
+                        bellman::gpu::OptParams {
+                            n_g1: 32 * 1024 * 1024,
+                            window_size_g1: 10,
+                            groups_g1: 807,
+                            n_g2: 16 * 1024 * 1024,
+                            window_size_g2: 9,
+                            groups_g2: 723,
+                        },
+                    )
+                })
+                .collect(),
+        )))
+    } else {
+        Backend::Cpu
+    }
+}
+
+/// CPU features that speed up the field arithmetic of the multiexp (When zoro
+/// is built with `-C target-cpu=native`)
+fn cpu_features() -> Vec<&'static str> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let mut features = Vec::new();
+        if is_x86_feature_detected!("avx2") {
+            features.push("avx2");
+        }
+        if is_x86_feature_detected!("bmi2") {
+            features.push("bmi2");
+        }
+        if is_x86_feature_detected!("adx") {
+            features.push("adx");
+        }
+        features
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        Vec::new()
+    }
+}
+
+fn log_backend_info(backend: &Backend, threads: usize) {
+    log::info!(
+        "CPU features: {}",
+        match cpu_features() {
+            features if features.is_empty() => "none detected".to_string(),
+            features => features.join(", "),
+        }
+    );
+    log::info!(
+        "Available parallelism: {}, proving threads: {}",
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        threads
+    );
+    if let Backend::Gpu(devices) = backend {
+        log::info!(
+            "Proving backend: GPU ({} devices)",
+            devices.lock().unwrap().len()
+        );
+    } else {
+        log::info!("Proving backend: CPU");
     }
 }
 
@@ -132,48 +399,34 @@ fn vk_to_hex(vk: &bellman::groth16::VerifyingKey<Bls12>) -> String {
     )
 }
 
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum ZoroError {
-    #[error("io error: {0}")]
-    IoError(#[from] std::io::Error),
-    #[error("node error: {0}")]
-    NodeError(#[from] bazuka::client::NodeError),
-    #[error("bank error: {0}")]
-    BankError(#[from] bank::BankError),
-    #[error("async task join error: {0}")]
-    JoinError(#[from] tokio::task::JoinError),
-    #[error("http server error: {0}")]
-    HttpServerError(#[from] hyper::Error),
-    #[error("http client error: {0}")]
-    HttpError(#[from] hyper::http::Error),
-    #[error("bincode error: {0}")]
-    BincodeError(#[from] bincode::Error),
-    #[error("json error: {0}")]
-    JsonError(#[from] serde_json::Error),
-    #[error("someone else found a block sooner than you!")]
-    Aborted,
-    #[error("you are not the validator anymore!")]
-    NotValidator,
-    #[error("http request timed out!")]
-    HttpTimeout(#[from] tokio::time::error::Elapsed),
-    #[error("from-hex error happened: {0}")]
-    FromHexError(#[from] hex::FromHexError),
-    #[error("kv-store error happened: {0}")]
-    KvStoreError(#[from] bazuka::db::KvStoreError),
-}
-
 type ZoroWork = bank::ZoroWork;
 
-fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
+/// Number of null transitions needed to fill a batch of `len` transitions
+fn batch_padding(len: usize, log4_batch_size: u8) -> Result<usize, ZoroError> {
+    let capacity = bank::batch_capacity(log4_batch_size);
+    if len > capacity {
+        return Err(ZoroError::BatchOverflow {
+            got: len,
+            max: capacity,
+        });
+    }
+    Ok(capacity - len)
+}
+
+fn to_zoro_work(address: Address, work: MpnWork) -> Result<ZoroWork, ZoroError> {
     use bazuka::core::hash::Hash;
     let commitment = bazuka::zk::ZkScalar::new(
         bazuka::core::Hasher::hash(&bincode::serialize(&(address.clone(), work.reward)).unwrap())
             .as_ref(),
     );
-    ZoroWork {
+    let enabled = match &work.data {
+        MpnWorkData::Deposit(deposits) => deposits.len(),
+        MpnWorkData::Withdraw(withdraws) => withdraws.len(),
+        MpnWorkData::Update(updates) => updates.len(),
+    };
+    Ok(ZoroWork {
         commitment,
+        enabled: Some(enabled),
         height: work.public_inputs.height.into(),
         state: work.public_inputs.state,
         aux_data: work.public_inputs.aux_data,
@@ -181,7 +434,13 @@ fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
         circuit: match &work.data {
             MpnWorkData::Deposit(deposits) => {
                 println!("{} deposits", deposits.len());
-                let padding = (1 << (2 * work.config.log4_deposit_batch_size)) - deposits.len();
+                let padding = batch_padding(deposits.len(), work.config.log4_deposit_batch_size)?;
+                #[cfg(feature = "metrics")]
+                metrics::gauge!(
+                    "zoro_batch_fill_ratio",
+                    deposits.len() as f64 / (deposits.len() + padding) as f64,
+                    "circuit" => "deposit"
+                );
                 let mut transitions = deposits.to_vec();
                 for _ in 0..padding {
                     transitions.push(DepositTransition::null(
@@ -203,7 +462,13 @@ fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
             }
             MpnWorkData::Withdraw(withdraws) => {
                 println!("{} withdraws", withdraws.len());
-                let padding = (1 << (2 * work.config.log4_withdraw_batch_size)) - withdraws.len();
+                let padding = batch_padding(withdraws.len(), work.config.log4_withdraw_batch_size)?;
+                #[cfg(feature = "metrics")]
+                metrics::gauge!(
+                    "zoro_batch_fill_ratio",
+                    withdraws.len() as f64 / (withdraws.len() + padding) as f64,
+                    "circuit" => "withdraw"
+                );
                 let mut transitions = withdraws.to_vec();
                 for _ in 0..padding {
                     transitions.push(WithdrawTransition::null(
@@ -225,7 +490,13 @@ fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
             }
             MpnWorkData::Update(updates) => {
                 println!("{} updates", updates.len());
-                let padding = (1 << (2 * work.config.log4_update_batch_size)) - updates.len();
+                let padding = batch_padding(updates.len(), work.config.log4_update_batch_size)?;
+                #[cfg(feature = "metrics")]
+                metrics::gauge!(
+                    "zoro_batch_fill_ratio",
+                    updates.len() as f64 / (updates.len() + padding) as f64,
+                    "circuit" => "update"
+                );
                 let mut transitions = updates.to_vec();
                 for _ in 0..padding {
                     transitions.push(UpdateTransition::null(
@@ -247,7 +518,29 @@ fn to_zoro_work(address: Address, work: MpnWork) -> ZoroWork {
                 })
             }
         },
+    })
+}
+
+/// Verifying keys the network checks MPN proofs against
+fn network_verify_keys() -> bank::ZoroVerifyKeys {
+    bank::ZoroVerifyKeys {
+        update: bazuka::config::blockchain::MPN_UPDATE_VK.clone(),
+        deposit: bazuka::config::blockchain::MPN_DEPOSIT_VK.clone(),
+        withdraw: bazuka::config::blockchain::MPN_WITHDRAW_VK.clone(),
+    }
+}
+
+fn fail_with_report(work: &ZoroWork, diagnosis: bank::Diagnosis, path: Option<&PathBuf>) -> ! {
+    println!("{} {}", "ERROR:".bright_red(), diagnosis);
+    if let Some(path) = path {
+        let report = DiagnosisReport {
+            work: work.to_string(),
+            diagnosis,
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&report).unwrap())
+            .expect("Unable to write report file!");
     }
+    std::process::exit(1);
 }
 
 fn alice_shuffle() {
@@ -276,6 +569,7 @@ async fn main() {
 
             load_params::<circuits::DepositCircuit, _>(
                 opt.deposit_circuit_params,
+                params::CircuitId::Deposit,
                 rng.clone(),
                 mpn_config.log4_tree_size,
                 mpn_config.log4_token_tree_size,
@@ -284,6 +578,7 @@ async fn main() {
 
             load_params::<circuits::WithdrawCircuit, _>(
                 opt.withdraw_circuit_params,
+                params::CircuitId::Withdraw,
                 rng.clone(),
                 mpn_config.log4_tree_size,
                 mpn_config.log4_token_tree_size,
@@ -292,6 +587,7 @@ async fn main() {
 
             load_params::<circuits::UpdateCircuit, _>(
                 opt.update_circuit_params,
+                params::CircuitId::Update,
                 rng.clone(),
                 mpn_config.log4_tree_size,
                 mpn_config.log4_token_tree_size,
@@ -299,15 +595,136 @@ async fn main() {
             );
         }
 
-        Opt::Prove(opt) => {
-            let verif_keys = bank::ZoroVerifyKeys {
-                update: bazuka::config::blockchain::MPN_UPDATE_VK.clone(),
-                deposit: bazuka::config::blockchain::MPN_DEPOSIT_VK.clone(),
-                withdraw: bazuka::config::blockchain::MPN_WITHDRAW_VK.clone(),
+        Opt::CountConstraints(opt) => {
+            let log4_tree_size = opt.log4_tree_size.unwrap_or(mpn_config.log4_tree_size);
+            let log4_token_tree_size = opt
+                .log4_token_tree_size
+                .unwrap_or(mpn_config.log4_token_tree_size);
+
+            count_constraints::<circuits::DepositCircuit>(
+                "Deposit",
+                log4_tree_size,
+                log4_token_tree_size,
+                opt.log4_deposit_batch_size
+                    .unwrap_or(mpn_config.log4_deposit_batch_size),
+            );
+
+            count_constraints::<circuits::WithdrawCircuit>(
+                "Withdraw",
+                log4_tree_size,
+                log4_token_tree_size,
+                opt.log4_withdraw_batch_size
+                    .unwrap_or(mpn_config.log4_withdraw_batch_size),
+            );
+
+            count_constraints::<circuits::UpdateCircuit>(
+                "Update",
+                log4_tree_size,
+                log4_token_tree_size,
+                opt.log4_update_batch_size
+                    .unwrap_or(mpn_config.log4_update_batch_size),
+            );
+        }
+
+        Opt::ExportR1cs(opt) => {
+            let log4_tree_size = opt.log4_tree_size.unwrap_or(mpn_config.log4_tree_size);
+            let log4_token_tree_size = opt
+                .log4_token_tree_size
+                .unwrap_or(mpn_config.log4_token_tree_size);
+
+            export_r1cs::<circuits::DepositCircuit>(
+                opt.deposit_r1cs,
+                log4_tree_size,
+                log4_token_tree_size,
+                opt.log4_deposit_batch_size
+                    .unwrap_or(mpn_config.log4_deposit_batch_size),
+            );
+
+            export_r1cs::<circuits::WithdrawCircuit>(
+                opt.withdraw_r1cs,
+                log4_tree_size,
+                log4_token_tree_size,
+                opt.log4_withdraw_batch_size
+                    .unwrap_or(mpn_config.log4_withdraw_batch_size),
+            );
+
+            export_r1cs::<circuits::UpdateCircuit>(
+                opt.update_r1cs,
+                log4_tree_size,
+                log4_token_tree_size,
+                opt.log4_update_batch_size
+                    .unwrap_or(mpn_config.log4_update_batch_size),
+            );
+        }
+
+        Opt::ProveWork(opt) => {
+            let work_file = File::open(opt.work).expect("Unable to open work file!");
+            let work: ZoroWork =
+                serde_json::from_reader(BufReader::new(work_file)).expect("Invalid work file!");
+
+            if opt.report.is_some() {
+                if let Err(e) = work.check_satisfied() {
+                    fail_with_report(&work, e.into(), opt.report.as_ref());
+                }
+            }
+
+            let params = read_params_file(opt.params, &work.circuit.params_header());
+
+            println!("Proving {}...", work);
+            alice_shuffle();
+            let start = std::time::Instant::now();
+            let mut rng: Box<dyn RngCore> = match opt.seed {
+                Some(seed) => Box::new(ChaCha20Rng::seed_from_u64(seed)),
+                None => Box::new(OsRng),
+            };
+            let vk = params.vk.clone().into();
+            let backend = make_backend(opt.gpu);
+            log_backend_info(&backend, rayon::current_num_threads());
+            log_memory_estimate(work.circuit.params_header().circuit, &params, &backend);
+            let proof = match work.prove_or_diagnose(&params, &vk, &mut rng, backend, None) {
+                Ok(proof) => proof,
+                Err(diagnosis) => fail_with_report(&work, diagnosis, opt.report.as_ref()),
             };
+            println!(
+                "{} {}ms",
+                "Proving took:".bright_green(),
+                start.elapsed().as_millis()
+            );
+
+            let proof_bytes = if opt.raw {
+                bank::proof_to_bytes(&bank::from_bazuka_proof(proof)).to_vec()
+            } else {
+                bincode::serialize(&proof).unwrap()
+            };
+            std::fs::write(opt.out, proof_bytes).expect("Unable to write proof file!");
+        }
+
+        Opt::VerifyWork(opt) => {
+            let work_file = File::open(opt.work).expect("Unable to open work file!");
+            let work: ZoroWork =
+                serde_json::from_reader(BufReader::new(work_file)).expect("Invalid work file!");
+            let proof_bytes = std::fs::read(opt.proof).expect("Unable to read proof file!");
+            let proof = if opt.raw {
+                bank::to_bazuka_proof(
+                    bank::proof_from_bytes(&proof_bytes).expect("Invalid proof file!"),
+                )
+            } else {
+                bincode::deserialize(&proof_bytes).expect("Invalid proof file!")
+            };
+            if work.verify_with_key(network_verify_keys().get(&work.circuit), &proof) {
+                println!("{} {}", "Proof is correct:".bright_green(), work);
+            } else {
+                println!("{} Proof is incorrect!", "ERROR:".bright_red());
+                std::process::exit(1);
+            }
+        }
+
+        Opt::Prove(opt) => {
+            let verif_keys = network_verify_keys();
 
             let deposit_params = load_params::<circuits::DepositCircuit, _>(
                 opt.deposit_circuit_params.clone(),
+                params::CircuitId::Deposit,
                 None::<ChaCha20Rng>,
                 mpn_config.log4_tree_size,
                 mpn_config.log4_token_tree_size,
@@ -321,6 +738,7 @@ async fn main() {
 
             let withdraw_params = load_params::<circuits::WithdrawCircuit, _>(
                 opt.withdraw_circuit_params.clone(),
+                params::CircuitId::Withdraw,
                 None::<ChaCha20Rng>,
                 mpn_config.log4_tree_size,
                 mpn_config.log4_token_tree_size,
@@ -334,6 +752,7 @@ async fn main() {
 
             let update_params = load_params::<circuits::UpdateCircuit, _>(
                 opt.update_circuit_params.clone(),
+                params::CircuitId::Update,
                 None::<ChaCha20Rng>,
                 mpn_config.log4_tree_size,
                 mpn_config.log4_token_tree_size,
@@ -351,41 +770,51 @@ async fn main() {
                 withdraw: withdraw_params.clone(),
             };
 
-            let backend = if opt.gpu {
-                Backend::Gpu(Arc::new(Mutex::new(
-                    Device::by_brand(Brand::Nvidia)
-                        .unwrap()
-                        .into_iter()
-                        .map(|d| {
-                            (
-                                d,
-                                bellman::gpu::OptParams {
-                                    n_g1: 32 * 1024 * 1024,
-                                    window_size_g1: 10,
-                                    groups_g1: 807,
-                                    n_g2: 16 * 1024 * 1024,
-                                    window_size_g2: 9,
-                                    groups_g2: 723,
-                                },
-                            )
-                        })
-                        .collect(),
-                )))
-            } else {
-                Backend::Cpu
-            };
+            #[cfg(feature = "metrics")]
+            {
+                metrics_exporter_prometheus::PrometheusBuilder::new()
+                    .install()
+                    .expect("Unable to start the Prometheus exporter!");
+                record_constraint_count::<circuits::DepositCircuit>(
+                    params::CircuitId::Deposit,
+                    mpn_config.log4_tree_size,
+                    mpn_config.log4_token_tree_size,
+                    mpn_config.log4_deposit_batch_size,
+                );
+                record_constraint_count::<circuits::WithdrawCircuit>(
+                    params::CircuitId::Withdraw,
+                    mpn_config.log4_tree_size,
+                    mpn_config.log4_token_tree_size,
+                    mpn_config.log4_withdraw_batch_size,
+                );
+                record_constraint_count::<circuits::UpdateCircuit>(
+                    params::CircuitId::Update,
+                    mpn_config.log4_tree_size,
+                    mpn_config.log4_token_tree_size,
+                    mpn_config.log4_update_batch_size,
+                );
+            }
+
+            let backend = make_backend(opt.gpu);
+            log_backend_info(&backend, opt.threads);
+            log_memory_estimate(params::CircuitId::Deposit, &deposit_params, &backend);
+            log_memory_estimate(params::CircuitId::Withdraw, &withdraw_params, &backend);
+            log_memory_estimate(params::CircuitId::Update, &update_params, &backend);
 
             let new_worker = || async {
                 let backend = backend.clone();
                 let zoro_params = zoro_params.clone();
+                let verif_keys = verif_keys.clone();
                 let opt = opt.clone();
                 loop {
                     let backend = backend.clone();
                     let zoro_params = zoro_params.clone();
+                    let verif_keys = verif_keys.clone();
                     let opt = opt.clone();
                     if let Err(e) = async move {
                         let backend = backend.clone();
                         let zoro_params = zoro_params.clone();
+                        let verif_keys = verif_keys.clone();
                         let opt = opt.clone();
                         let cancel = Arc::new(RwLock::new(false));
 
@@ -436,25 +865,44 @@ async fn main() {
                                 alice_shuffle();
                                 let start = std::time::Instant::now();
                                 let pool = rayon::ThreadPoolBuilder::new()
-                                    .num_threads(32)
+                                    .num_threads(opt.threads)
                                     .build()
                                     .unwrap();
                                 let prover_address = opt.address.clone();
+                                let check_satisfied = opt.check_satisfied;
                                 let proofs = tokio::task::spawn_blocking(move || {
                                     pool.install(|| -> Result<
                                     HashMap<usize, bazuka::zk::groth16::Groth16Proof>,
-                                    bank::BankError,
+                                    ZoroError,
                                 > {
                                     works
                                         .works
                                         .into_par_iter()
                                         .map(|(id, p)| {
-                                            to_zoro_work(prover_address.clone(), p).prove(
-                                                zoro_params.clone(),
-                                                backend.clone(),
-                                                Some(cancel.clone()),
-                                            )
-                                            .map(|p| (id, p))
+                                            let work = to_zoro_work(prover_address.clone(), p)?;
+                                            let params = zoro_params.get(&work.circuit);
+                                            let vk = verif_keys.get(&work.circuit);
+                                            let cancel = Some(cancel.clone());
+                                            let proof = if check_satisfied {
+                                                work.check_satisfied()?;
+                                                work.prove_or_diagnose(
+                                                    params,
+                                                    vk,
+                                                    &mut OsRng,
+                                                    backend.clone(),
+                                                    cancel,
+                                                )
+                                                .map_err(bank::BankError::Diagnosed)?
+                                            } else {
+                                                work.prove(
+                                                    params,
+                                                    vk,
+                                                    &mut OsRng,
+                                                    backend.clone(),
+                                                    cancel,
+                                                )?
+                                            };
+                                            Ok((id, proof))
                                         })
                                         .collect()
                                 })
@@ -466,11 +914,7 @@ async fn main() {
                                     start.elapsed().as_millis()
                                 );
                                 if start.elapsed() > MAXIMUM_PROVING_TIME {
-                                    println!(
-                                        "{} {}",
-                                        "WARNING:".bright_red(),
-                                        "Proving time too high!"
-                                    );
+                                    println!("{} Proving time too high!", "WARNING:".bright_red());
                                 }
 
                                 let resp = client
@@ -507,3 +951,29 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_padding() {
+        assert_eq!(batch_padding(0, 2).unwrap(), 16);
+        assert_eq!(batch_padding(10, 2).unwrap(), 6);
+        assert_eq!(batch_padding(16, 2).unwrap(), 0);
+        assert!(matches!(
+            batch_padding(17, 2),
+            Err(ZoroError::BatchOverflow { got: 17, max: 16 })
+        ));
+    }
+
+    #[test]
+    fn test_log_backend_info() {
+        log_backend_info(&Backend::Cpu, 4);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            cpu_features().contains(&"avx2"),
+            is_x86_feature_detected!("avx2")
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use bazuka::core::hash::Hash;
+use bellman::groth16;
+use bls12_381::Bls12;
+
+use std::io::{Read, Write};
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"ZORO";
+const VERSION: u8 = 2;
+
+#[derive(Error, Debug)]
+pub enum ParamsError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("not a zoro parameters file!")]
+    InvalidMagic,
+    #[error("unsupported parameters file version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown circuit id: {0}")]
+    UnknownCircuit(u8),
+    #[error("parameters file is for {found}, expected {expected}!")]
+    HeaderMismatch {
+        found: ParamsHeader,
+        expected: ParamsHeader,
+    },
+    #[error("parameters were generated for a different verifying key!")]
+    FingerprintMismatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitId {
+    Deposit = 0,
+    Withdraw = 1,
+    Update = 2,
+}
+
+impl TryFrom<u8> for CircuitId {
+    type Error = ParamsError;
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(CircuitId::Deposit),
+            1 => Ok(CircuitId::Withdraw),
+            2 => Ok(CircuitId::Update),
+            _ => Err(ParamsError::UnknownCircuit(v)),
+        }
+    }
+}
+
+impl std::fmt::Display for CircuitId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitId::Deposit => write!(f, "deposit"),
+            CircuitId::Withdraw => write!(f, "withdraw"),
+            CircuitId::Update => write!(f, "update"),
+        }
+    }
+}
+
+/// Identifies the circuit (And its sizes) a parameters file was generated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParamsHeader {
+    pub circuit: CircuitId,
+    pub log4_tree_size: u8,
+    pub log4_token_tree_size: u8,
+    pub log4_batch_size: u8,
+}
+
+impl std::fmt::Display for ParamsHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (tree: {}, token-tree: {}, batch: {})",
+            self.circuit, self.log4_tree_size, self.log4_token_tree_size, self.log4_batch_size
+        )
+    }
+}
+
+fn fingerprint(vk: &bazuka::zk::groth16::Groth16VerifyingKey) -> [u8; 32] {
+    let mut fingerprint = [0u8; 32];
+    fingerprint
+        .copy_from_slice(bazuka::core::Hasher::hash(&bincode::serialize(vk).unwrap()).as_ref());
+    fingerprint
+}
+
+/// Hash of the verifying key, as serialized for bazuka
+pub fn vk_fingerprint(vk: &groth16::VerifyingKey<Bls12>) -> [u8; 32] {
+    fingerprint(&vk.clone().into())
+}
+
+/// Fingerprint of the verifying key the network expects for the circuit
+pub fn network_vk_fingerprint(circuit: CircuitId) -> [u8; 32] {
+    fingerprint(&match circuit {
+        CircuitId::Deposit => bazuka::config::blockchain::MPN_DEPOSIT_VK.clone(),
+        CircuitId::Withdraw => bazuka::config::blockchain::MPN_WITHDRAW_VK.clone(),
+        CircuitId::Update => bazuka::config::blockchain::MPN_UPDATE_VK.clone(),
+    })
+}
+
+/// Applied on top of the estimated allocations: the prover's vectors grow by
+/// doubling, so their capacity can be up to twice their length, and the
+/// allocator adds overhead of its own
+const MEMORY_SAFETY_FACTOR: usize = 2;
+
+/// Bucket memory of bellman's CPU multiexp over `n` exponents, with all its
+/// `c`-bit windows in flight at once
+fn multiexp_buckets(n: usize, projective_size: usize) -> usize {
+    let c = if n < 32 {
+        3
+    } else {
+        (n as f64).ln().ceil() as usize
+    };
+    256usize.div_ceil(c) * (1 << c) * projective_size
+}
+
+fn opt_param<T: TryInto<usize>>(value: T) -> usize {
+    value
+        .try_into()
+        .unwrap_or_else(|_| panic!("OptParams value doesn't fit in usize!"))
+}
+
+/// Upper-ish bound of the memory a single proof needs on top of the (Shared)
+/// parameters, so that a scheduler can avoid running out of memory. It adds
+/// up the a/b/c evaluation domains and the quotient polynomial, the
+/// input/aux assignments and the copies handed to the multiexps, the density
+/// trackers, and the buckets of the eight CPU multiexps as if they all ran at
+/// once. Each `OptParams` in `gpu` adds host copies of up to `n_g1`/`n_g2`
+/// bases and of `groups << window_size` buckets. That part is a guess, as
+/// the bellman fork's GPU code isn't checked here. The total is multiplied by
+/// `MEMORY_SAFETY_FACTOR`.
+pub fn estimate_proving_memory<'a>(
+    params: &groth16::Parameters<Bls12>,
+    gpu: impl IntoIterator<Item = &'a bellman::gpu::OptParams>,
+) -> usize {
+    use std::mem::size_of;
+    let scalar = size_of::<bls12_381::Scalar>();
+    let g1 = size_of::<bls12_381::G1Projective>();
+    let g2 = size_of::<bls12_381::G2Projective>();
+
+    let domain_size = params.h.len() + 1;
+    let inputs = params.vk.ic.len();
+    let aux = params.l.len();
+    let evaluations = 4 * domain_size * scalar;
+    let assignments = 2 * (inputs + aux) * scalar;
+    let densities = 3 * (inputs + aux + 7) / 8;
+    let buckets = multiexp_buckets(params.h.len(), g1)
+        + 3 * multiexp_buckets(aux, g1)
+        + 2 * multiexp_buckets(inputs, g1)
+        + multiexp_buckets(aux, g2)
+        + multiexp_buckets(inputs, g2);
+    let gpu: usize = gpu
+        .into_iter()
+        .map(|opt| {
+            opt_param(opt.n_g1) * size_of::<bls12_381::G1Affine>()
+                + opt_param(opt.n_g2) * size_of::<bls12_381::G2Affine>()
+                + (opt_param(opt.groups_g1) << opt_param(opt.window_size_g1)) * g1
+                + (opt_param(opt.groups_g2) << opt_param(opt.window_size_g2)) * g2
+        })
+        .sum();
+    MEMORY_SAFETY_FACTOR * (evaluations + assignments + densities + buckets + gpu)
+}
+
+pub fn write_params<W: Write>(
+    mut w: W,
+    params: &groth16::Parameters<Bls12>,
+    header: &ParamsHeader,
+) -> Result<(), ParamsError> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[
+        VERSION,
+        header.circuit as u8,
+        header.log4_tree_size,
+        header.log4_token_tree_size,
+        header.log4_batch_size,
+    ])?;
+    w.write_all(&vk_fingerprint(&params.vk))?;
+    params.write(&mut w)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Reads a parameters file, failing before the (Large) parameters are read
+/// if they weren't generated for the expected circuit and verifying key.
+/// Files written before the header was introduced are read as raw parameters
+/// and only checked against the verifying key, in which case no header is
+/// returned.
+pub fn read_params<R: Read>(
+    mut r: R,
+    expected: &ParamsHeader,
+    expected_fingerprint: &[u8; 32],
+) -> Result<(groth16::Parameters<Bls12>, Option<ParamsHeader>), ParamsError> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        let params = groth16::Parameters::<Bls12>::read(
+            (&magic[..]).chain(r),
+            false, /* false for better performance*/
+        )?;
+        if vk_fingerprint(&params.vk) != *expected_fingerprint {
+            return Err(ParamsError::FingerprintMismatch);
+        }
+        return Ok((params, None));
+    }
+    let mut header = [0u8; 5];
+    r.read_exact(&mut header)?;
+    if header[0] != VERSION {
+        return Err(ParamsError::UnsupportedVersion(header[0]));
+    }
+    let header = ParamsHeader {
+        circuit: header[1].try_into()?,
+        log4_tree_size: header[2],
+        log4_token_tree_size: header[3],
+        log4_batch_size: header[4],
+    };
+    if header != *expected {
+        return Err(ParamsError::HeaderMismatch {
+            found: header,
+            expected: *expected,
+        });
+    }
+    let mut fingerprint = [0u8; 32];
+    r.read_exact(&mut fingerprint)?;
+    if fingerprint != *expected_fingerprint {
+        return Err(ParamsError::FingerprintMismatch);
+    }
+    let params =
+        groth16::Parameters::<Bls12>::read(r, false /* false for better performance*/)?;
+    // The stored fingerprint only describes what the writer claimed
+    if vk_fingerprint(&params.vk) != *expected_fingerprint {
+        return Err(ParamsError::FingerprintMismatch);
+    }
+    Ok((params, Some(header)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::tests::TestBatch;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn test_header(log4_batch_size: u8) -> ParamsHeader {
+        ParamsHeader {
+            circuit: CircuitId::Deposit,
+            log4_tree_size: 0,
+            log4_token_tree_size: 0,
+            log4_batch_size,
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_depends_on_circuit_size() {
+        let small = vk_fingerprint(&TestBatch::params(1).vk);
+        assert_eq!(small, vk_fingerprint(&TestBatch::params(1).vk));
+        assert_ne!(small, vk_fingerprint(&TestBatch::params(2).vk));
+    }
+
+    #[test]
+    fn test_memory_estimate_grows_with_batch_size() {
+        let small = estimate_proving_memory(&TestBatch::params(1), []);
+        let large = estimate_proving_memory(&TestBatch::params(2), []);
+        assert!(small > 0);
+        assert!(large > small);
+    }
+
+    #[test]
+    fn test_memory_estimate_counts_gpu_buffers() {
+        let params = TestBatch::params(1);
+        let opt = bellman::gpu::OptParams {
+            n_g1: 1024,
+            window_size_g1: 4,
+            groups_g1: 8,
+            n_g2: 512,
+            window_size_g2: 4,
+            groups_g2: 8,
+        };
+        let cpu = estimate_proving_memory(&params, []);
+        let one_gpu = estimate_proving_memory(&params, [&opt]);
+        let two_gpus = estimate_proving_memory(&params, [&opt, &opt]);
+        assert!(one_gpu > cpu + 2 * 1024 * std::mem::size_of::<bls12_381::G1Affine>());
+        assert_eq!(two_gpus - one_gpu, one_gpu - cpu);
+    }
+
+    #[test]
+    fn test_read_params_checks_fingerprint() {
+        let params = TestBatch::params(1);
+        let mut file = Vec::new();
+        write_params(&mut file, &params, &test_header(1)).unwrap();
+
+        let (read, _) =
+            read_params(&file[..], &test_header(1), &vk_fingerprint(&params.vk)).unwrap();
+        assert_eq!(vk_fingerprint(&read.vk), vk_fingerprint(&params.vk));
+
+        let other = vk_fingerprint(&TestBatch::params(2).vk);
+        assert!(matches!(
+            read_params(&file[..], &test_header(1), &other),
+            Err(ParamsError::FingerprintMismatch)
+        ));
+
+        // A valid header followed by the body of different parameters
+        let swapped = groth16::generate_random_parameters::<Bls12, _, _>(
+            TestBatch::new(1),
+            &mut ChaCha20Rng::seed_from_u64(654321),
+        )
+        .unwrap();
+        file.truncate(MAGIC.len() + 5 + 32);
+        swapped.write(&mut file).unwrap();
+        assert!(matches!(
+            read_params(&file[..], &test_header(1), &vk_fingerprint(&params.vk)),
+            Err(ParamsError::FingerprintMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_params_roundtrip() {
+        let params = TestBatch::params(1);
+        let fingerprint = vk_fingerprint(&params.vk);
+        let header = test_header(1);
+        let mut file = Vec::new();
+        write_params(&mut file, &params, &header).unwrap();
+
+        let (read, read_header) = read_params(&file[..], &header, &fingerprint).unwrap();
+        assert_eq!(read_header, Some(header));
+        assert_eq!(read.h.len(), params.h.len());
+        assert_eq!(read.l.len(), params.l.len());
+
+        let wrong_circuit = ParamsHeader {
+            circuit: CircuitId::Withdraw,
+            ..header
+        };
+        assert!(matches!(
+            read_params(&file[..], &wrong_circuit, &fingerprint),
+            Err(ParamsError::HeaderMismatch { found, .. }) if found == header
+        ));
+        assert!(matches!(
+            read_params(&file[..], &test_header(2), &fingerprint),
+            Err(ParamsError::HeaderMismatch { found, .. }) if found == header
+        ));
+
+        file[5] = 7; // Circuit id
+        assert!(matches!(
+            read_params(&file[..], &header, &fingerprint),
+            Err(ParamsError::UnknownCircuit(7))
+        ));
+    }
+
+    #[test]
+    fn test_read_legacy_params() {
+        let params = TestBatch::params(1);
+        let mut file = Vec::new();
+        params.write(&mut file).unwrap();
+
+        let (read, header) =
+            read_params(&file[..], &test_header(1), &vk_fingerprint(&params.vk)).unwrap();
+        assert_eq!(header, None);
+        assert_eq!(vk_fingerprint(&read.vk), vk_fingerprint(&params.vk));
+
+        let other = vk_fingerprint(&TestBatch::params(2).vk);
+        assert!(matches!(
+            read_params(&file[..], &test_header(1), &other),
+            Err(ParamsError::FingerprintMismatch)
+        ));
+    }
+}